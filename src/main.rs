@@ -1,7 +1,10 @@
 use ariadne::{Label, Report, ReportKind, Source};
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::WalkBuilder;
 use rayon::prelude::*;
 use serde_json::Value;
+use similar::TextDiff;
 use std::fs::File;
 use std::io::{Read, Write};
 use std::process::{Command, Stdio};
@@ -14,7 +17,14 @@ use which::which;
     version = "0.1.4",
     author = "Yingchi Long <longyingchi24s@ict.ac.cn>"
 )]
-struct Args {
+struct Cli {
+    #[command(subcommand)]
+    command: Commands,
+}
+
+/// Options shared by every subcommand.
+#[derive(clap::Args)]
+struct CommonArgs {
     /// Path to the nixf-tidy executable
     #[arg(long)]
     nixf_tidy_path: Option<String>,
@@ -33,41 +43,270 @@ struct Args {
     #[arg(short, long)]
     only: Option<String>,
 
-    /// Automatically apply fixes to source files
-    #[arg(long)]
-    auto_fix: bool,
+    /// Output format for reported diagnostics
+    #[arg(long, value_enum, default_value = "human")]
+    format: OutputFormat,
+}
+
+/// How reported diagnostics are rendered.
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum OutputFormat {
+    /// Fancy ariadne reports on stderr (the default)
+    Human,
+    /// A stable JSON array of diagnostic objects on stdout
+    Json,
+    /// One `file:line:col: severity: message [code]` line per diagnostic on stdout
+    Errfmt,
+}
 
-    /// Input source files
-    files: Vec<String>,
+#[derive(Subcommand)]
+enum Commands {
+    /// Report diagnostics without modifying any files
+    Check {
+        #[command(flatten)]
+        common: CommonArgs,
+
+        /// Exclude files matching this glob, whether named directly or found by walking a directory (repeatable)
+        #[arg(long = "exclude", value_name = "GLOB")]
+        exclude: Vec<String>,
+
+        /// Input source files, or directories to walk recursively for *.nix files
+        files: Vec<String>,
+    },
+
+    /// Automatically apply all available fixes
+    Fix {
+        #[command(flatten)]
+        common: CommonArgs,
+
+        /// Preview what would change as a unified diff, without writing files
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Exclude files matching this glob, whether named directly or found by walking a directory (repeatable)
+        #[arg(long = "exclude", value_name = "GLOB")]
+        exclude: Vec<String>,
+
+        /// Input source files, or directories to walk recursively for *.nix files
+        files: Vec<String>,
+    },
+
+    /// Apply exactly one diagnostic's fix at a given position
+    Single {
+        #[command(flatten)]
+        common: CommonArgs,
+
+        /// File to fix
+        file: String,
+
+        /// Position of the diagnostic to fix, as a byte offset or line:column
+        position: String,
+    },
 }
 
 type NixfReport<'a> = (Report<(&'a str, std::ops::Range<usize>)>, &'a str, Source);
 
+/// Maximum number of auto-fix passes to run over a single file before giving
+/// up. Some fixes can re-trigger the diagnostic they were meant to resolve;
+/// this bounds how long we'll chase a fixpoint that may never converge.
+const MAX_AUTO_FIX_ITERATIONS: u32 = 10;
+
 #[derive(Debug, Clone)]
 struct Edit {
     range: std::ops::Range<usize>,
     new_text: String,
+    /// `sname` of the diagnostic this edit came from, kept around so a
+    /// rejected edit can be reported back to the user.
+    sname: String,
+}
+
+/// Result of applying a batch of edits to some content: the edits that were
+/// actually applied, and those that had to be dropped because their range
+/// collided with one that was already accepted.
+struct ApplyResult {
+    content: String,
+    applied: Vec<Edit>,
+    rejected: Vec<Edit>,
 }
 
-fn apply_fixes_to_content(content: &str, edits: &[Edit]) -> String {
+/// Apply all non-conflicting edits from `edits` to `content` in one pass.
+///
+/// Edits are sorted by `range.start`; whenever two edits overlap (the
+/// previous edit's `range.end` is past the next edit's `range.start`) the
+/// earliest-starting one is kept and the other is rejected, ensuring the
+/// applied set never corrupts `content` with overlapping byte ranges.
+fn apply_fixes_to_content(content: &str, edits: &[Edit]) -> ApplyResult {
     if edits.is_empty() {
-        return content.to_string();
+        return ApplyResult {
+            content: content.to_string(),
+            applied: vec![],
+            rejected: vec![],
+        };
     }
 
-    // Sort fixes by start position in reverse order to apply from end to beginning.
-    // This is to avoid the location markers from getting out of sync once the first
-    // edit is done.
     let mut sorted_fixes = edits.to_vec();
-    sorted_fixes.sort_by(|a, b| b.range.start.cmp(&a.range.start));
+    sorted_fixes.sort_by(|a, b| a.range.start.cmp(&b.range.start));
+
+    let mut applied: Vec<Edit> = Vec::new();
+    let mut rejected: Vec<Edit> = Vec::new();
+    let mut last_end = 0usize;
+    for edit in sorted_fixes {
+        if applied.is_empty() || edit.range.start >= last_end {
+            last_end = edit.range.end;
+            applied.push(edit);
+        } else {
+            rejected.push(edit);
+        }
+    }
 
+    // Apply fixes from end to beginning so earlier offsets stay valid as
+    // later edits are applied.
+    let mut to_apply = applied.clone();
+    to_apply.sort_by(|a, b| b.range.start.cmp(&a.range.start));
     let mut result = content.to_string();
-    for fix in sorted_fixes {
+    for fix in &to_apply {
         if fix.range.end <= result.len() {
             result.replace_range(fix.range.clone(), &fix.new_text);
         }
     }
 
-    result
+    ApplyResult {
+        content: result,
+        applied,
+        rejected,
+    }
+}
+
+/// Build a colored unified diff between `original` and `updated`, in the
+/// style of `git diff`: red `-` lines, green `+` lines, cyan `@@` hunk
+/// headers. Returns `None` when there is nothing to show.
+///
+/// Rendered into a single `String` (rather than printed directly) so the
+/// caller can buffer it on the `FileReport` and emit it with one write after
+/// `run_reports` joins all the parallel workers — otherwise interleaved
+/// `println!` calls from different files would garble each other's diffs.
+fn format_dry_run_diff(input_file: &str, original: &str, updated: &str) -> Option<String> {
+    if original == updated {
+        return None;
+    }
+
+    let diff = TextDiff::from_lines(original, updated);
+    let unified = diff
+        .unified_diff()
+        .context_radius(3)
+        .header(input_file, input_file)
+        .to_string();
+
+    let mut rendered = String::new();
+    for line in unified.lines() {
+        if line.starts_with("@@") {
+            rendered.push_str(&format!("\x1b[36m{line}\x1b[0m\n"));
+        } else if line.starts_with("+++") || line.starts_with("---") {
+            rendered.push_str(&format!("\x1b[1m{line}\x1b[0m\n"));
+        } else if line.starts_with('-') {
+            rendered.push_str(&format!("\x1b[31m{line}\x1b[0m\n"));
+        } else if line.starts_with('+') {
+            rendered.push_str(&format!("\x1b[32m{line}\x1b[0m\n"));
+        } else {
+            rendered.push_str(&format!("{line}\n"));
+        }
+    }
+    Some(rendered)
+}
+
+/// What `process_file` should do with the fixes it collects.
+enum FixMode {
+    /// Only build reports; never touch the file.
+    Check,
+    /// Apply fixes to a fixpoint, writing the result (or previewing it).
+    Fix { dry_run: bool },
+    /// Apply only the one fix covering `position` (a byte offset).
+    Single { position: usize },
+}
+
+/// Parse a `single` position argument as either a raw byte offset or a
+/// `line:column` pair (1-indexed, as editors report them).
+fn resolve_position(content: &str, position: &str) -> Option<usize> {
+    if let Some((line, column)) = position.split_once(':') {
+        let line: usize = line.parse().ok()?;
+        let column: usize = column.parse().ok()?;
+        if line == 0 || column == 0 {
+            return None;
+        }
+
+        // Find the real byte offset of `line`'s start by scanning for `\n`,
+        // rather than assuming a fixed-width terminator: `str::lines()`
+        // strips a trailing `\r` too, so `len() + 1` undercounts CRLF files.
+        let mut line_start = 0;
+        for _ in 1..line {
+            line_start = content[line_start..]
+                .find('\n')
+                .map(|p| line_start + p + 1)?;
+        }
+
+        let line_end = content[line_start..]
+            .find('\n')
+            .map(|p| line_start + p)
+            .unwrap_or(content.len());
+        let raw_line = &content[line_start..line_end];
+        let line_content = raw_line.strip_suffix('\r').unwrap_or(raw_line);
+
+        let column_offset: usize = line_content
+            .chars()
+            .take(column - 1)
+            .map(|c| c.len_utf8())
+            .sum();
+        Some(line_start + column_offset)
+    } else {
+        position.parse().ok()
+    }
+}
+
+fn build_exclude_globset(excludes: &[String]) -> GlobSet {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in excludes {
+        match Glob::new(pattern) {
+            Ok(glob) => {
+                builder.add(glob);
+            }
+            Err(e) => eprintln!("Warning: invalid --exclude pattern '{pattern}': {e}"),
+        }
+    }
+    builder
+        .build()
+        .unwrap_or_else(|e| panic!("Failed to build --exclude globset: {e}"))
+}
+
+/// Expand `entries` into a flat list of files, honoring `.gitignore`/`.ignore`
+/// when an entry is a directory. `--exclude` globs filter both the files found
+/// in directories and entries named directly. Resolved up front so
+/// the rest of the pipeline keeps treating `files` as a plain file list.
+fn resolve_files(entries: &[String], excludes: &[String]) -> Vec<String> {
+    let exclude_set = build_exclude_globset(excludes);
+    let mut resolved = vec![];
+
+    for entry in entries {
+        let path = std::path::Path::new(entry);
+        if path.is_dir() {
+            for result in WalkBuilder::new(path).build() {
+                match result {
+                    Ok(dir_entry) => {
+                        let p = dir_entry.path();
+                        let is_nix_file = dir_entry.file_type().is_some_and(|t| t.is_file())
+                            && p.extension().and_then(|e| e.to_str()) == Some("nix");
+                        if is_nix_file && !exclude_set.is_match(p) {
+                            resolved.push(p.display().to_string());
+                        }
+                    }
+                    Err(e) => eprintln!("Warning: failed to walk '{entry}': {e}"),
+                }
+            }
+        } else if !exclude_set.is_match(path) {
+            resolved.push(entry.clone());
+        }
+    }
+
+    resolved
 }
 
 fn build_char_byte_table(s: &str) -> Vec<usize> {
@@ -84,14 +323,12 @@ fn byte_to_char_offset(table: &[usize], byte_pos: usize) -> usize {
     table.binary_search(&byte_pos).unwrap()
 }
 
-fn process_file<'a>(
-    variable_lookup: bool,
+fn run_nixf_tidy(
     nixf_tidy_path: &str,
-    ignore_rules: &[String],
-    only: &Option<String>,
-    auto_fix: bool,
-    input_file: &'a str,
-) -> Vec<NixfReport<'a>> {
+    variable_lookup: bool,
+    content: &str,
+    input_file: &str,
+) -> Result<Value, String> {
     let mut cmd = Command::new(nixf_tidy_path);
     cmd.stdin(Stdio::piped()).stdout(Stdio::piped());
 
@@ -99,233 +336,638 @@ fn process_file<'a>(
         cmd.arg("--variable-lookup");
     }
 
-    let mut input = String::new();
-    File::open(input_file)
-        .unwrap_or_else(|e| panic!("Failed to open {}: {}", input_file, e))
-        .read_to_string(&mut input)
-        .unwrap_or_else(|e| panic!("Failed to read {}: {}", input_file, e));
-
     let mut child = cmd
         .spawn()
-        .unwrap_or_else(|e| panic!("Failed to execute nixf-tidy: {}", e));
+        .map_err(|e| format!("failed to execute nixf-tidy: {e}"))?;
     child
         .stdin
         .as_mut()
         .unwrap()
-        .write_all(input.as_bytes())
-        .unwrap();
-
-    let char_byte_table = build_char_byte_table(&input);
+        .write_all(content.as_bytes())
+        .map_err(|e| format!("failed to write to nixf-tidy stdin: {e}"))?;
 
     let output = child
         .wait_with_output()
-        .unwrap_or_else(|e| panic!("Failed to read output: {}", e));
+        .map_err(|e| format!("failed to read nixf-tidy output: {e}"))?;
 
     if !output.status.success() {
-        eprintln!("nixf-tidy failed on file '{}'", input_file);
-        return vec![];
+        eprintln!("nixf-tidy failed on file '{input_file}'");
+        return Ok(Value::Array(vec![]));
     }
 
-    let stdout = String::from_utf8(output.stdout).unwrap_or_default();
-    let diagnostics: Value = match serde_json::from_str(&stdout) {
-        Ok(v) => v,
-        Err(e) => {
-            eprintln!("Failed to parse JSON from nixf-tidy output: {}", e);
-            return vec![];
+    let stdout = String::from_utf8(output.stdout)
+        .map_err(|_| "nixf-tidy produced non-UTF8 output".to_string())?;
+
+    serde_json::from_str(&stdout)
+        .map_err(|e| format!("failed to parse JSON from nixf-tidy output: {e}"))
+}
+
+/// A single diagnostic note, already formatted (args substituted into the
+/// message template) and with its byte range resolved.
+#[derive(Debug, Clone)]
+struct NoteInfo {
+    message: String,
+    start_byte: usize,
+    end_byte: usize,
+}
+
+/// One of nixf-tidy's suggested fixes for a diagnostic.
+#[derive(Debug, Clone)]
+struct FixInfo {
+    edits: Vec<Edit>,
+}
+
+/// A diagnostic parsed out of nixf-tidy's native JSON, independent of how it
+/// will eventually be rendered (ariadne report, `--format json`, `errfmt`).
+#[derive(Debug, Clone)]
+struct DiagnosticInfo {
+    sname: String,
+    message: String,
+    severity: i64,
+    start_byte: usize,
+    end_byte: usize,
+    notes: Vec<NoteInfo>,
+    fixes: Vec<FixInfo>,
+}
+
+fn format_with_args(template: &str, args: &Value) -> String {
+    let mut formatted = template.to_string();
+    if let Some(args_array) = args.as_array() {
+        for arg in args_array {
+            if let Some(arg_str) = arg.as_str() {
+                formatted = formatted.replacen("{}", arg_str, 1);
+            }
         }
+    }
+    formatted
+}
+
+fn byte_range(range: &Value) -> Option<std::ops::Range<usize>> {
+    let start = range
+        .get("lCur")
+        .and_then(|s| s.get("offset").and_then(|o| o.as_u64()))?;
+    let end = range
+        .get("rCur")
+        .and_then(|e| e.get("offset").and_then(|o| o.as_u64()))?;
+    Some((start as usize)..(end as usize))
+}
+
+/// Parse nixf-tidy's diagnostics into [`DiagnosticInfo`]s, applying
+/// `--ignore`/`--only` filtering. This is the single source of truth
+/// consumed by every render format and by auto-fix edit collection.
+fn parse_diagnostics(
+    diagnostics: &Value,
+    ignore_rules: &[String],
+    only: &Option<String>,
+) -> Vec<DiagnosticInfo> {
+    let mut parsed = vec![];
+
+    let Some(diags) = diagnostics.as_array() else {
+        return parsed;
     };
 
-    let mut reports = vec![];
-    let mut all_edits = vec![];
-
-    if let Some(diags) = diagnostics.as_array() {
-        for diag in diags {
-            if let (
-                Some(sname),
-                Some(message),
-                Some(spans),
-                Some(severity),
-                Some(args),
-                Some(notes),
-                Some(fixes), // Vec<Fix>, // Fix = { edits, message }
-            ) = (
-                diag.get("sname"),
-                diag.get("message"),
-                diag.get("range"),
-                diag.get("severity"),
-                diag.get("args"),
-                diag.get("notes"),
-                diag.get("fixes"),
-            ) {
-                if let Some(rule) = only {
-                    if rule != sname {
-                        continue; // Ignore all except --only
-                    }
-                }
+    for diag in diags {
+        let (
+            Some(sname),
+            Some(message),
+            Some(spans),
+            Some(severity),
+            Some(args),
+            Some(notes),
+            Some(fixes),
+        ) = (
+            diag.get("sname"),
+            diag.get("message"),
+            diag.get("range"),
+            diag.get("severity"),
+            diag.get("args"),
+            diag.get("notes"),
+            diag.get("fixes"),
+        )
+        else {
+            continue;
+        };
+
+        let sname = sname.as_str().unwrap_or_default();
+
+        if let Some(rule) = only {
+            if rule != sname {
+                continue; // Ignore all except --only
+            }
+        }
 
-                if ignore_rules.iter().any(|rule| rule == sname) {
-                    continue; // Ignore this diagnostic
-                }
+        if ignore_rules.iter().any(|rule| rule == sname) {
+            continue; // Ignore this diagnostic
+        }
 
-                // Collect fixes for auto-fix functionality
-                // TODO: We currently limit this to one edit per file per run, until
-                // https://github.com/inclyc/nixf-diagnose/issues/13
-                // is sorted out.
-                if auto_fix && all_edits.len() == 0 {
-                    if let Some(fixes_array) = fixes.as_array() {
-                        if fixes_array.len() > 0 {
-                            if fixes_array.len() > 1 {
-                                eprintln!(
-                                    "Warning: Multiple fixes found for a single diagnostic. Only the first fix will be applied to '{input_file}'."
-                                );
-                            }
-                            let first_fix = fixes_array.first().unwrap();
-                            if let Some(edits) = first_fix.get("edits").and_then(|e| e.as_array()) {
-                                for edit in edits {
-                                    if let (Some(new_text), Some(range)) = (
-                                        edit.get("newText").and_then(|t| t.as_str()),
-                                        edit.get("range"),
-                                    ) {
-                                        if let (Some(start), Some(end)) = (
-                                            range
-                                                .get("lCur")
-                                                .and_then(|s| s.get("offset").and_then(|o| o.as_u64())),
-                                            range
-                                                .get("rCur")
-                                                .and_then(|e| e.get("offset").and_then(|o| o.as_u64())),
-                                        ) {
-                                            all_edits.push(Edit {
-                                                range: (start as usize)..(end as usize),
-                                                new_text: new_text.to_string(),
-                                            });
-                                        }
-                                    }
-                                }
-                                continue
-                            }
-                        }
-                    }
+        let Some(range) = byte_range(spans) else {
+            continue;
+        };
+
+        let mut fix_infos = vec![];
+        if let Some(fixes_array) = fixes.as_array() {
+            for fix in fixes_array {
+                if let Some(fix_edits) = fix.get("edits").and_then(|e| e.as_array()) {
+                    let edits = fix_edits
+                        .iter()
+                        .filter_map(|edit| {
+                            let new_text = edit.get("newText").and_then(|t| t.as_str())?;
+                            let range = byte_range(edit.get("range")?)?;
+                            Some(Edit {
+                                range,
+                                new_text: new_text.to_string(),
+                                sname: sname.to_string(),
+                            })
+                        })
+                        .collect();
+                    fix_infos.push(FixInfo { edits });
                 }
+            }
+        }
 
-                let report_kind = match severity.as_i64().unwrap_or(1) {
-                    0 => ReportKind::Error,
-                    1 => ReportKind::Error,
-                    2 => ReportKind::Warning,
-                    3 => ReportKind::Advice,
-                    4 => ReportKind::Advice,
-                    _ => ReportKind::Error,
-                };
-
-                let mut formatted_message = message.as_str().unwrap_or("Unknown error").to_string();
-                if let Some(args_array) = args.as_array() {
-                    for arg in args_array {
-                        if let Some(arg_str) = arg.as_str() {
-                            formatted_message = formatted_message.replacen("{}", arg_str, 1);
-                        }
-                    }
-                }
+        let notes = notes
+            .as_array()
+            .map(|notes_array| {
+                notes_array
+                    .iter()
+                    .filter_map(|note| {
+                        let note_message = note.get("message")?;
+                        let note_args = note.get("args")?;
+                        let note_range = byte_range(note.get("range")?)?;
+                        Some(NoteInfo {
+                            message: format_with_args(
+                                note_message.as_str().unwrap_or("Unknown note"),
+                                note_args,
+                            ),
+                            start_byte: note_range.start,
+                            end_byte: note_range.end,
+                        })
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        parsed.push(DiagnosticInfo {
+            sname: sname.to_string(),
+            message: format_with_args(message.as_str().unwrap_or("Unknown error"), args),
+            severity: severity.as_i64().unwrap_or(1),
+            start_byte: range.start,
+            end_byte: range.end,
+            notes,
+            fixes: fix_infos,
+        });
+    }
 
-                if let (Some(start), Some(end)) = (
-                    spans
-                        .get("lCur")
-                        .and_then(|s| s.get("offset").and_then(|o| o.as_u64())),
-                    spans
-                        .get("rCur")
-                        .and_then(|e| e.get("offset").and_then(|o| o.as_u64())),
-                ) {
-                    let start_char = byte_to_char_offset(&char_byte_table, start as usize);
-                    let end_char = byte_to_char_offset(&char_byte_table, end as usize);
-                    let mut report = Report::build(report_kind, input_file, start_char)
-                        .with_message(&formatted_message)
-                        .with_label(
-                            Label::new((input_file, start_char..end_char))
-                                .with_message(&formatted_message),
-                        )
-                        .with_code(sname.as_str().unwrap());
-
-                    if let Some(notes_array) = notes.as_array() {
-                        for note in notes_array {
-                            if let (Some(note_message), Some(note_args), Some(note_spans)) =
-                                (note.get("message"), note.get("args"), note.get("range"))
-                            {
-                                let mut formatted_note_message =
-                                    note_message.as_str().unwrap_or("Unknown note").to_string();
-                                if let Some(note_args_array) = note_args.as_array() {
-                                    for arg in note_args_array {
-                                        if let Some(arg_str) = arg.as_str() {
-                                            formatted_note_message =
-                                                formatted_note_message.replacen("{}", arg_str, 1);
-                                        }
-                                    }
-                                }
-
-                                if let (Some(note_start), Some(note_end)) = (
-                                    note_spans
-                                        .get("lCur")
-                                        .and_then(|s| s.get("offset").and_then(|o| o.as_u64())),
-                                    note_spans
-                                        .get("rCur")
-                                        .and_then(|e| e.get("offset").and_then(|o| o.as_u64())),
-                                ) {
-                                    let start_char =
-                                        byte_to_char_offset(&char_byte_table, note_start as usize);
-                                    let end_char =
-                                        byte_to_char_offset(&char_byte_table, note_end as usize);
-                                    report = report.with_label(
-                                        Label::new((input_file, start_char..end_char))
-                                            .with_message(&formatted_note_message),
-                                    );
-                                }
-                            }
-                        }
-                    }
-                    reports.push((report.finish(), input_file, Source::from(&input)));
+    parsed
+}
+
+/// Warn that a diagnostic offered more than one fix and only the first is
+/// being considered, shared by every place that picks a diagnostic's first
+/// fix (auto-fix collection, `single`).
+fn warn_multiple_fixes(input_file: &str) {
+    eprintln!(
+        "Warning: Multiple fixes found for a single diagnostic. Only the first fix will be considered for '{input_file}'."
+    );
+}
+
+/// Warn that an edit was dropped because its range collided with one that
+/// was already accepted, shared by every caller of
+/// [`apply_fixes_to_content`].
+fn warn_rejected_edit(rejected: &Edit, input_file: &str) {
+    eprintln!(
+        "Warning: skipping conflicting fix for '{sname}' in '{input_file}'",
+        sname = rejected.sname
+    );
+}
+
+/// Collect the edits auto-fix should try to apply in this pass: the first
+/// fix of each diagnostic. Selecting a non-overlapping subset of those is
+/// left to [`apply_fixes_to_content`].
+fn collect_auto_fix_edits(diagnostics: &[DiagnosticInfo], input_file: &str) -> Vec<Edit> {
+    let mut edits = vec![];
+    for diag in diagnostics {
+        if let Some(first_fix) = diag.fixes.first() {
+            if diag.fixes.len() > 1 {
+                warn_multiple_fixes(input_file);
+            }
+            edits.extend(first_fix.edits.iter().cloned());
+        }
+    }
+    edits
+}
+
+fn severity_report_kind(severity: i64) -> ReportKind<'static> {
+    match severity {
+        0 => ReportKind::Error,
+        1 => ReportKind::Error,
+        2 => ReportKind::Warning,
+        3 => ReportKind::Advice,
+        4 => ReportKind::Advice,
+        _ => ReportKind::Error,
+    }
+}
+
+fn severity_label(severity: i64) -> &'static str {
+    match severity {
+        0 => "error",
+        1 => "error",
+        2 => "warning",
+        3 => "advice",
+        4 => "advice",
+        _ => "error",
+    }
+}
+
+/// Render diagnostics as ariadne [`Report`]s for the human-readable format.
+fn build_ariadne_reports<'a>(
+    diagnostics: &[DiagnosticInfo],
+    input_file: &'a str,
+    content: &str,
+) -> Vec<NixfReport<'a>> {
+    let char_byte_table = build_char_byte_table(content);
+
+    diagnostics
+        .iter()
+        .map(|diag| {
+            let start_char = byte_to_char_offset(&char_byte_table, diag.start_byte);
+            let end_char = byte_to_char_offset(&char_byte_table, diag.end_byte);
+            let mut report =
+                Report::build(severity_report_kind(diag.severity), input_file, start_char)
+                    .with_message(&diag.message)
+                    .with_label(
+                        Label::new((input_file, start_char..end_char)).with_message(&diag.message),
+                    )
+                    .with_code(&diag.sname);
+
+            for note in &diag.notes {
+                let start_char = byte_to_char_offset(&char_byte_table, note.start_byte);
+                let end_char = byte_to_char_offset(&char_byte_table, note.end_byte);
+                report = report.with_label(
+                    Label::new((input_file, start_char..end_char)).with_message(&note.message),
+                );
+            }
+
+            (report.finish(), input_file, Source::from(content))
+        })
+        .collect()
+}
+
+/// Convert a byte offset into a 1-indexed (line, column) pair, counting
+/// columns in chars as editors do.
+fn byte_to_line_col(content: &str, byte_pos: usize) -> (usize, usize) {
+    let mut line = 1;
+    let mut line_start = 0;
+    for (i, c) in content.char_indices() {
+        if i >= byte_pos {
+            break;
+        }
+        if c == '\n' {
+            line += 1;
+            line_start = i + 1;
+        }
+    }
+    let column = content[line_start..byte_pos].chars().count() + 1;
+    (line, column)
+}
+
+/// Render one diagnostic as a `file:line:col: severity: message [code]` line
+/// for `:grep`/quickfix-style editor integration.
+fn render_errfmt_line(input_file: &str, content: &str, diag: &DiagnosticInfo) -> String {
+    let (line, column) = byte_to_line_col(content, diag.start_byte);
+    format!(
+        "{input_file}:{line}:{column}: {severity}: {message} [{sname}]",
+        severity = severity_label(diag.severity),
+        message = diag.message,
+        sname = diag.sname
+    )
+}
+
+/// Render one diagnostic as a stable JSON object for downstream tooling.
+fn diagnostic_to_json(input_file: &str, content: &str, diag: &DiagnosticInfo) -> Value {
+    let (start_line, start_col) = byte_to_line_col(content, diag.start_byte);
+    let (end_line, end_col) = byte_to_line_col(content, diag.end_byte);
+
+    serde_json::json!({
+        "file": input_file,
+        "range": {
+            "start_byte": diag.start_byte,
+            "end_byte": diag.end_byte,
+            "start_line": start_line,
+            "start_col": start_col,
+            "end_line": end_line,
+            "end_col": end_col,
+        },
+        "severity": severity_label(diag.severity),
+        "sname": diag.sname,
+        "message": diag.message,
+        "notes": diag.notes.iter().map(|note| {
+            serde_json::json!({
+                "message": note.message,
+                "start_byte": note.start_byte,
+                "end_byte": note.end_byte,
+            })
+        }).collect::<Vec<_>>(),
+        "fixes": diag.fixes.iter().map(|fix| {
+            serde_json::json!({
+                "edits": fix.edits.iter().map(|edit| {
+                    serde_json::json!({
+                        "start_byte": edit.range.start,
+                        "end_byte": edit.range.end,
+                        "new_text": edit.new_text,
+                    })
+                }).collect::<Vec<_>>(),
+            })
+        }).collect::<Vec<_>>(),
+    })
+}
+
+/// Everything gathered about one processed file, independent of how it will
+/// be rendered.
+struct FileReport<'a> {
+    input_file: &'a str,
+    content: String,
+    diagnostics: Vec<DiagnosticInfo>,
+    /// Rendered `--dry-run` diff, if any, buffered here so it can be printed
+    /// after all files have finished processing in parallel.
+    dry_run_diff: Option<String>,
+}
+
+/// Read `input_file` into a string, reporting (rather than panicking on) I/O
+/// errors and non-UTF8 content so one bad file doesn't abort a batch run.
+fn read_file_content(input_file: &str) -> Option<String> {
+    let mut content = String::new();
+    let result = File::open(input_file).and_then(|mut f| f.read_to_string(&mut content));
+    match result {
+        Ok(_) => Some(content),
+        Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+            eprintln!("{input_file} contains non-UTF8 content; skipping");
+            None
+        }
+        Err(e) => {
+            eprintln!("skipping {input_file}: {e}");
+            None
+        }
+    }
+}
+
+fn process_file<'a>(
+    variable_lookup: bool,
+    nixf_tidy_path: &str,
+    ignore_rules: &[String],
+    only: &Option<String>,
+    mode: &FixMode,
+    input_file: &'a str,
+) -> Option<FileReport<'a>> {
+    let mut content = read_file_content(input_file)?;
+    let original_content = content.clone();
+
+    if let FixMode::Single { position } = mode {
+        let raw_diagnostics =
+            match run_nixf_tidy(nixf_tidy_path, variable_lookup, &content, input_file) {
+                Ok(v) => v,
+                Err(e) => {
+                    eprintln!("skipping {input_file}: {e}");
+                    return None;
+                }
+            };
+        let diagnostics = parse_diagnostics(&raw_diagnostics, ignore_rules, only);
+        let diagnostic = diagnostics
+            .iter()
+            .find(|d| (d.start_byte..d.end_byte).contains(position));
+        match diagnostic.and_then(|d| d.fixes.first().map(|fix| (d, fix))) {
+            Some((diag, fix)) => {
+                if diag.fixes.len() > 1 {
+                    warn_multiple_fixes(input_file);
+                }
+                let result = apply_fixes_to_content(&content, &fix.edits);
+                for rejected in &result.rejected {
+                    warn_rejected_edit(rejected, input_file);
                 }
+                if let Err(e) = std::fs::write(input_file, &result.content) {
+                    eprintln!("Failed to write fixed content to {input_file}: {e}");
+                } else {
+                    eprintln!("Applied fix for '{}' to {input_file}", diag.sname);
+                }
+            }
+            None => {
+                eprintln!("No fixable diagnostic covers position {position} in '{input_file}'");
             }
         }
+        return Some(FileReport {
+            input_file,
+            content,
+            diagnostics,
+            dry_run_diff: None,
+        });
     }
 
-    // Apply edits if auto_fix is enabled
-    if auto_fix && !all_edits.is_empty() {
-        let fixed_content = apply_fixes_to_content(&input, &all_edits);
-        if let Err(e) = std::fs::write(input_file, fixed_content) {
-            eprintln!("Failed to write fixed content to {input_file}: {e}");
-        } else {
-            eprintln!("Applied {} edits to {}", all_edits.len(), input_file);
+    let auto_fix = matches!(mode, FixMode::Fix { .. });
+
+    let mut diagnostics;
+    let mut iteration = 0u32;
+    loop {
+        let raw_diagnostics =
+            match run_nixf_tidy(nixf_tidy_path, variable_lookup, &content, input_file) {
+                Ok(v) => v,
+                Err(e) => {
+                    eprintln!("skipping {input_file}: {e}");
+                    return None;
+                }
+            };
+        diagnostics = parse_diagnostics(&raw_diagnostics, ignore_rules, only);
+
+        if !auto_fix {
+            break;
+        }
+
+        let edits = collect_auto_fix_edits(&diagnostics, input_file);
+        if edits.is_empty() {
+            break;
+        }
+
+        if iteration >= MAX_AUTO_FIX_ITERATIONS {
+            eprintln!(
+                "Warning: '{input_file}' did not converge after {MAX_AUTO_FIX_ITERATIONS} auto-fix passes; leaving remaining diagnostics in place."
+            );
+            break;
+        }
+
+        let result = apply_fixes_to_content(&content, &edits);
+        for rejected in &result.rejected {
+            warn_rejected_edit(rejected, input_file);
+        }
+        if result.applied.is_empty() {
+            break;
         }
+
+        content = result.content;
+        iteration += 1;
     }
 
-    reports
+    let mut dry_run_diff = None;
+    if auto_fix && content != original_content {
+        match mode {
+            FixMode::Fix { dry_run: true } => {
+                dry_run_diff = format_dry_run_diff(input_file, &original_content, &content);
+            }
+            _ => {
+                if let Err(e) = std::fs::write(input_file, &content) {
+                    eprintln!("Failed to write fixed content to {input_file}: {e}");
+                } else {
+                    eprintln!("Applied fixes to {input_file} in {iteration} pass(es)");
+                }
+            }
+        }
+    }
+
+    Some(FileReport {
+        input_file,
+        content,
+        diagnostics,
+        dry_run_diff,
+    })
 }
 
-fn main() {
-    let args = Args::parse();
-
-    // Try to determine nixf-tidy path in order:
-    // 1. Provided CLI argument
-    // 2. Compile-time constant (from build script)
-    // 3. Runtime discovery via `which`
-    let nixf_tidy_path = args
-        .nixf_tidy_path
+/// Resolve the nixf-tidy executable in order: CLI argument, compile-time
+/// constant (from build script), runtime discovery via `which`.
+fn resolve_nixf_tidy_path(explicit: Option<String>) -> String {
+    explicit
         .or(option_env!("NIXF_TIDY_PATH").map(|s| s.to_string()))
         .or(which("nixf-tidy").ok().map(|p| p.display().to_string()))
-        .expect("nixf-tidy executable not found in PATH or --nixf-tidy-path not provided");
-
-    let files = args.files;
-    let variable_lookup = args.variable_lookup;
-    let auto_fix = args.auto_fix;
-    let ignore = args.ignore;
-    let only = args.only;
+        .expect("nixf-tidy executable not found in PATH or --nixf-tidy-path not provided")
+}
 
-    let all_reports: Vec<_> = files
+fn run_reports<'a>(
+    nixf_tidy_path: &str,
+    common: &CommonArgs,
+    mode: FixMode,
+    files: &'a [String],
+) -> Vec<FileReport<'a>> {
+    files
         .par_iter()
-        .flat_map(|file| process_file(variable_lookup, &nixf_tidy_path, &ignore, &only, auto_fix, file))
-        .collect();
+        .filter_map(|file| {
+            process_file(
+                common.variable_lookup,
+                nixf_tidy_path,
+                &common.ignore,
+                &common.only,
+                &mode,
+                file,
+            )
+        })
+        .collect()
+}
+
+/// Render the collected file reports to stdout/stderr according to
+/// `format`, returning whether any diagnostics were reported at all (used
+/// to decide the process exit code).
+fn render_file_reports(file_reports: &[FileReport], format: OutputFormat) -> bool {
+    let any_diagnostics = file_reports.iter().any(|r| !r.diagnostics.is_empty());
+
+    match format {
+        OutputFormat::Human => {
+            for file_report in file_reports {
+                let reports = build_ariadne_reports(
+                    &file_report.diagnostics,
+                    file_report.input_file,
+                    &file_report.content,
+                );
+                for (report, input_file, source) in reports {
+                    report.eprint((input_file, source)).unwrap();
+                }
+            }
+        }
+        OutputFormat::Json => {
+            let all: Vec<Value> = file_reports
+                .iter()
+                .flat_map(|file_report| {
+                    file_report.diagnostics.iter().map(|diag| {
+                        diagnostic_to_json(file_report.input_file, &file_report.content, diag)
+                    })
+                })
+                .collect();
+            println!("{}", Value::Array(all));
+        }
+        OutputFormat::Errfmt => {
+            for file_report in file_reports {
+                for diag in &file_report.diagnostics {
+                    println!(
+                        "{}",
+                        render_errfmt_line(file_report.input_file, &file_report.content, diag)
+                    );
+                }
+            }
+        }
+    }
+
+    any_diagnostics
+}
 
-    if !all_reports.is_empty() {
-        for (report, input_file, source) in all_reports {
-            report.eprint((input_file, source)).unwrap();
+/// Print every buffered `--dry-run` diff, one `print!` per file, after
+/// `run_reports` has joined all the parallel workers.
+fn render_dry_run_diffs(file_reports: &[FileReport]) {
+    for file_report in file_reports {
+        if let Some(diff) = &file_report.dry_run_diff {
+            print!("{diff}");
         }
+    }
+}
+
+fn main() {
+    let cli = Cli::parse();
+
+    // Bound here at the top of `main` (rather than used inside the match
+    // arms) so `files` outlives the `FileReport`s that borrow from it below.
+    let (common, mode, format, files) = match cli.command {
+        Commands::Check {
+            common,
+            exclude,
+            files,
+        } => {
+            let format = common.format;
+            let files = resolve_files(&files, &exclude);
+            (common, FixMode::Check, format, files)
+        }
+        Commands::Fix {
+            common,
+            dry_run,
+            exclude,
+            files,
+        } => {
+            let format = common.format;
+            let files = resolve_files(&files, &exclude);
+            (common, FixMode::Fix { dry_run }, format, files)
+        }
+        Commands::Single {
+            common,
+            file,
+            position,
+        } => {
+            let format = common.format;
+            let Some(content) = read_file_content(&file) else {
+                std::process::exit(1);
+            };
+            let Some(byte_position) = resolve_position(&content, &position) else {
+                eprintln!("Invalid position '{position}'; expected a byte offset or line:column");
+                std::process::exit(1);
+            };
+            (
+                common,
+                FixMode::Single {
+                    position: byte_position,
+                },
+                format,
+                vec![file],
+            )
+        }
+    };
+
+    let nixf_tidy_path = resolve_nixf_tidy_path(common.nixf_tidy_path.clone());
+    let file_reports = run_reports(&nixf_tidy_path, &common, mode, &files);
+
+    render_dry_run_diffs(&file_reports);
+
+    if render_file_reports(&file_reports, format) {
         std::process::exit(1);
     }
 }